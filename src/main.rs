@@ -5,10 +5,514 @@ extern crate csv;
 extern crate libc;
 extern crate time;
 
-use clap::{App, AppSettings, Arg};
-use libc::{c_long, getrusage, rusage, suseconds_t, time_t, timeval, RUSAGE_CHILDREN};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use libc::{c_long, rusage, suseconds_t, time_t, timeval};
+use std::mem;
 use std::process::Command;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The measurements collected for a single run of `command`.
+struct RunStats {
+    user_ns: u64,
+    system_ns: u64,
+    real_ns: u64,
+    peak_mem: c_long,
+    major_faults: c_long,
+    minor_faults: c_long,
+    in_blocks: c_long,
+    out_blocks: c_long,
+    exit_code: i32,
+    // set when the child was killed by a signal rather than exiting normally
+    signal: Option<i32>,
+    core_dumped: bool,
+    rss_series: Option<Vec<RssSample>>,
+}
+
+/// Budget thresholds a run must stay within, e.g. for use as a CI regression gate.
+struct Budgets {
+    max_real_ns: Option<u64>,
+    max_user_ns: Option<u64>,
+    max_mem_kb: Option<u64>,
+    max_faults: Option<u64>,
+}
+
+/// Parses human-readable durations like "500ms", "2s", or "1m30s" into nanoseconds.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let mut chars = s.chars().peekable();
+    let mut total_ns = 0f64;
+    let mut matched = false;
+
+    loop {
+        let mut numstr = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() || c == '.' {
+                numstr.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if numstr.is_empty() {
+            break;
+        }
+
+        let mut unit = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_alphabetic() {
+                unit.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let value: f64 = numstr
+            .parse()
+            .map_err(|_| format!("invalid number '{}' in duration '{}'", numstr, s))?;
+        let ns_per_unit = match unit.as_str() {
+            "ns" => 1f64,
+            "us" | "µs" => 1_000f64,
+            "ms" => 1_000_000f64,
+            "s" => 1_000_000_000f64,
+            "m" => 60f64 * 1_000_000_000f64,
+            "h" => 3_600f64 * 1_000_000_000f64,
+            "" => return Err(format!("missing unit in duration '{}' (e.g. 500ms, 2s, 1m30s)", s)),
+            _ => return Err(format!("unknown duration unit '{}' in '{}'", unit, s)),
+        };
+        total_ns += value * ns_per_unit;
+        matched = true;
+    }
+
+    if !matched || chars.peek().is_some() {
+        return Err(format!(
+            "invalid duration '{}'; expected forms like 500ms, 2s, 1m30s",
+            s
+        ));
+    }
+    Ok(total_ns as u64)
+}
+
+/// Parses human-readable sizes like "128MB" or "2GB" into bytes.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split_at = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (numstr, unit) = s.split_at(split_at);
+    if numstr.is_empty() {
+        return Err(format!("invalid size '{}'", s));
+    }
+    let value: f64 = numstr
+        .parse()
+        .map_err(|_| format!("invalid number '{}' in size '{}'", numstr, s))?;
+    let bytes_per_unit = match unit.to_uppercase().as_str() {
+        "" | "B" => 1f64,
+        "KB" | "K" => 1024f64,
+        "MB" | "M" => 1024f64 * 1024f64,
+        "GB" | "G" => 1024f64 * 1024f64 * 1024f64,
+        _ => return Err(format!("unknown size unit '{}' in '{}'", unit, s)),
+    };
+    Ok((value * bytes_per_unit) as u64)
+}
+
+/// Checks measured values against `budgets`, returning one message per violated budget.
+fn check_budgets(budgets: &Budgets, real_ns: u64, user_ns: u64, mem_kb: u64, faults: u64) -> Vec<String> {
+    let mut violations = Vec::new();
+    if let Some(max) = budgets.max_real_ns {
+        if real_ns > max {
+            violations.push(format!(
+                "real time {:.3}s exceeded --max-real {:.3}s",
+                real_ns as f64 / 1e9,
+                max as f64 / 1e9
+            ));
+        }
+    }
+    if let Some(max) = budgets.max_user_ns {
+        if user_ns > max {
+            violations.push(format!(
+                "user time {:.3}s exceeded --max-user {:.3}s",
+                user_ns as f64 / 1e9,
+                max as f64 / 1e9
+            ));
+        }
+    }
+    if let Some(max) = budgets.max_mem_kb {
+        if mem_kb > max {
+            violations.push(format!(
+                "peak memory {:.1}MB exceeded --max-mem {:.1}MB",
+                mem_kb as f64 / 1024.0,
+                max as f64 / 1024.0
+            ));
+        }
+    }
+    if let Some(max) = budgets.max_faults {
+        if faults > max {
+            violations.push(format!(
+                "{} page faults exceeded --max-faults {}",
+                faults, max
+            ));
+        }
+    }
+    violations
+}
+
+/// Maps a signal number to its conventional symbolic name.
+fn signal_name(sig: i32) -> String {
+    let name = match sig {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("SIG{}", sig),
+    };
+    name.to_string()
+}
+
+fn zeroed_rusage() -> rusage {
+    rusage {
+        ru_utime: timeval {
+            tv_sec: 0 as time_t,
+            tv_usec: 0 as suseconds_t,
+        },
+        ru_stime: timeval {
+            tv_sec: 0 as time_t,
+            tv_usec: 0 as suseconds_t,
+        },
+        ru_maxrss: 0 as c_long,
+        ru_ixrss: 0 as c_long,
+        ru_idrss: 0 as c_long,
+        ru_isrss: 0 as c_long,
+        ru_minflt: 0 as c_long,
+        ru_majflt: 0 as c_long,
+        ru_nswap: 0 as c_long,
+        ru_inblock: 0 as c_long,
+        ru_oublock: 0 as c_long,
+        ru_msgsnd: 0 as c_long,
+        ru_msgrcv: 0 as c_long,
+        ru_nsignals: 0 as c_long,
+        ru_nvcsw: 0 as c_long,
+        ru_nivcsw: 0 as c_long,
+    }
+}
+
+// getrusage(RUSAGE_CHILDREN) is cumulative across every child the process has ever
+// reaped, so it can't tell two runs of --repeat apart. wait4(2) instead returns the
+// rusage of just the one child being reaped here, so we use it for both the wait
+// and the measurements in one syscall.
+fn wait4_child(pid: libc::pid_t) -> (libc::c_int, rusage) {
+    let mut status: libc::c_int = 0;
+    let mut usage = zeroed_rusage();
+    unsafe {
+        if libc::wait4(pid, &mut status, 0, &mut usage) < 0 {
+            process::exit(1);
+        }
+    }
+    (status, usage)
+}
+
+// glibc's <sys/wait.h> WIF*/W* macros operate on the raw status word wait4(2)
+// fills in; libc doesn't expose them as functions, so reimplement the bit layout.
+fn wifexited(status: libc::c_int) -> bool {
+    status & 0x7f == 0
+}
+
+fn wexitstatus(status: libc::c_int) -> libc::c_int {
+    (status >> 8) & 0xff
+}
+
+fn wtermsig(status: libc::c_int) -> libc::c_int {
+    status & 0x7f
+}
+
+fn wcoredump(status: libc::c_int) -> bool {
+    status & 0x80 != 0
+}
+
+fn timeval_ns(t: &timeval) -> u64 {
+    t.tv_sec as u64 * 1_000_000_000 + t.tv_usec as u64 * 1_000
+}
+
+fn precise_duration_ns(d: &time::Duration) -> u64 {
+    let ns: u64 = if let Some(ns) = d.num_nanoseconds() {
+        ns as u64 - d.num_seconds() as u64 * 1_000_000_000
+    } else if let Some(us) = d.num_microseconds() {
+        us as u64 - d.num_seconds() as u64 * 1_000_000
+    } else {
+        let ms = d.num_milliseconds();
+        ms as u64 - d.num_seconds() as u64 * 1_000
+    };
+    d.num_seconds() as u64 * 1_000_000_000 + ns
+}
+
+/// Read a process' current resident set size from procfs.
+///
+/// Returns `None` on platforms without `/proc`, or if the process has
+/// already exited by the time we try to read its status.
+#[cfg(target_os = "linux")]
+fn read_rss_kb(pid: u32) -> Option<i64> {
+    use std::fs;
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    for line in status.lines() {
+        if line.starts_with("VmRSS:") {
+            return line.split_whitespace().nth(1)?.parse().ok();
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_rss_kb(_pid: u32) -> Option<i64> {
+    None
+}
+
+/// One (elapsed_ns, rss_kb) observation of a child's resident memory.
+type RssSample = (u64, i64);
+
+/// Polls a child's RSS at `interval` until told to stop, recording the
+/// series relative to `start`.
+fn sample_rss(
+    pid: u32,
+    interval: Duration,
+    start: time::PreciseTime,
+    stop: Arc<AtomicBool>,
+) -> Vec<RssSample> {
+    let mut series = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        if let Some(rss) = read_rss_kb(pid) {
+            series.push((precise_duration_ns(&start.to(time::PreciseTime::now())), rss));
+        }
+        thread::sleep(interval);
+    }
+    series
+}
+
+/// Summary statistics over an RSS series, plus a compact sparkline.
+struct RssSummary {
+    mean: f64,
+    p50: i64,
+    p90: i64,
+    p99: i64,
+    time_to_peak_ns: u64,
+    sparkline: String,
+}
+
+fn summarize_rss(series: &[RssSample]) -> RssSummary {
+    let mut rss: Vec<i64> = series.iter().map(|&(_, kb)| kb).collect();
+    rss.sort();
+    let percentile = |p: f64| rss[(((rss.len() - 1) as f64) * p).round() as usize];
+
+    let (peak_ns, _) = series
+        .iter()
+        // earliest sample at the peak, so a plateau counts from when it was reached
+        .max_by_key(|&&(ns, kb)| (kb, std::cmp::Reverse(ns)))
+        .cloned()
+        .unwrap_or((0, 0));
+
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = series.iter().map(|&(_, kb)| kb).max().unwrap_or(0);
+    let min = series.iter().map(|&(_, kb)| kb).min().unwrap_or(0);
+    let sparkline: String = series
+        .iter()
+        .map(|&(_, kb)| {
+            if max == min {
+                BLOCKS[0]
+            } else {
+                let frac = (kb - min) as f64 / (max - min) as f64;
+                BLOCKS[(frac * (BLOCKS.len() - 1) as f64).round() as usize]
+            }
+        })
+        .collect();
+
+    RssSummary {
+        mean: rss.iter().sum::<i64>() as f64 / rss.len() as f64,
+        p50: percentile(0.50),
+        p90: percentile(0.90),
+        p99: percentile(0.99),
+        time_to_peak_ns: peak_ns,
+        sparkline,
+    }
+}
+
+// build a fresh Command for `cmd` every time we want to run it again
+fn build_command(cmd: &str, cmd_args: &ArgMatches) -> Command {
+    let mut command = Command::new(cmd);
+    if let Some(args) = cmd_args.values_of("") {
+        command.args(args);
+    }
+    command
+}
+
+// run `command` once to completion and gather the stats attributable to just this run.
+// if `sample_interval` is given, also records the child's RSS over its lifetime.
+fn run_once(cmd: &str, cmd_args: &ArgMatches, sample_interval: Option<Duration>) -> RunStats {
+    let child = match build_command(cmd, cmd_args).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            use std::io::ErrorKind;
+            match e.kind() {
+                ErrorKind::NotFound => {
+                    process::exit(127);
+                }
+                ErrorKind::PermissionDenied => {
+                    process::exit(126);
+                }
+                _ => {}
+            }
+            match e.raw_os_error() {
+                Some(e) if e > 0 && e <= 125 => {
+                    process::exit(125);
+                }
+                _ => process::exit(1),
+            }
+        }
+    };
+
+    let start = time::PreciseTime::now();
+
+    let sampler = sample_interval.map(|interval| {
+        let pid = child.id();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_thread = stop.clone();
+        let handle = thread::spawn(move || sample_rss(pid, interval, start, stop_thread));
+        (stop, handle)
+    });
+
+    let pid = child.id() as libc::pid_t;
+    let (status, usage) = wait4_child(pid);
+    let end = time::PreciseTime::now();
+    // the child has been reaped by wait4_child above, so std no longer owns its pid;
+    // don't let it try to wait() or kill() a pid that may have been recycled by now
+    mem::forget(child);
+
+    let (exit_code, signal, core_dumped) = if wifexited(status) {
+        (wexitstatus(status), None, false)
+    } else {
+        // terminated by a signal rather than exiting normally; follow the shell
+        // convention so the cause is still visible in $?
+        let sig = wtermsig(status);
+        (128 + sig, Some(sig), wcoredump(status))
+    };
+
+    let real_ns = precise_duration_ns(&start.to(end));
+
+    let rss_series = sampler.map(|(stop, handle)| {
+        stop.store(true, Ordering::Relaxed);
+        let mut series = handle.join().unwrap_or_default();
+        if series.is_empty() {
+            // the child exited before we got a single sample in -- fall back to
+            // the one data point we do have.
+            series.push((real_ns, usage.ru_maxrss));
+        }
+        series
+    });
+
+    RunStats {
+        user_ns: timeval_ns(&usage.ru_utime),
+        system_ns: timeval_ns(&usage.ru_stime),
+        real_ns,
+        // wait4's rusage describes just this child (and any of its own descendants
+        // it already reaped), so ru_maxrss is this run's actual peak, not a running
+        // high-water mark shared with unrelated runs.
+        peak_mem: usage.ru_maxrss,
+        major_faults: usage.ru_majflt,
+        minor_faults: usage.ru_minflt,
+        in_blocks: usage.ru_inblock,
+        out_blocks: usage.ru_oublock,
+        exit_code,
+        signal,
+        core_dumped,
+        rss_series,
+    }
+}
+
+/// Summary statistics for one metric across a series of runs.
+struct Aggregate {
+    min: f64,
+    max: f64,
+    mean: f64,
+    median: f64,
+    stddev: f64,
+    total: f64,
+}
+
+fn aggregate(values: &[f64]) -> Aggregate {
+    let n = values.len() as f64;
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let total: f64 = values.iter().sum();
+    let mean = total / n;
+    let mid = sorted.len() / 2;
+    let median = if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    };
+    let stddev = if values.len() < 2 {
+        0.0
+    } else {
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        variance.sqrt()
+    };
+
+    Aggregate {
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        mean,
+        median,
+        stddev,
+        total,
+    }
+}
+
+fn fmt_ns(ns: f64) -> String {
+    format!("{:.3}ms", ns / 1_000_000.0)
+}
+
+fn fmt_kb(kb: f64) -> String {
+    format!("{:.1}MB", kb / 1024.0)
+}
+
+fn fmt_count(n: f64) -> String {
+    format!("{:.1}", n)
+}
+
+// picks the unit formatter for a given metric (milliseconds for times, MB for
+// memory, plain counts for faults), so aggregate mode never prints a raw
+// nanosecond/kilobyte count by mistake.
+fn metric_fmt(name: &str) -> fn(f64) -> String {
+    match name {
+        "peak_mem" => fmt_kb,
+        "major_faults" | "minor_faults" => fmt_count,
+        _ => fmt_ns,
+    }
+}
 
 fn main() {
     let mut app = App::new("tally")
@@ -97,7 +601,134 @@ value. The metrics are:
   real: elapsed wall clock time (in nanoseconds)
   peak_mem: max resident memory (in kbytes)
   major_faults: major page faults
-  minor_faults: minor page faults",
+  minor_faults: minor page faults
+
+When --repeat is given, each metric instead gets one row per
+summary statistic, named \"<metric>_<statistic>\" (e.g. \"real_mean\").",
+                ),
+        )
+        .arg(
+            Arg::with_name("repeat")
+                .long("repeat")
+                .takes_value(true)
+                .help("Run command N times and report aggregate statistics.")
+                .validator(|v| match v.parse::<usize>() {
+                    Ok(0) | Err(_) => Err(String::from("--repeat takes a positive integer")),
+                    Ok(_) => Ok(()),
+                })
+                .long_help(
+                    "\
+Runs `command` N times instead of once, and reports min, max, mean,
+median, and standard deviation for each metric, plus a total. This
+is useful for microbenchmarking, where the noise of a single run
+can be misleading.",
+                ),
+        )
+        .arg(
+            Arg::with_name("warmup")
+                .long("warmup")
+                .takes_value(true)
+                .requires("repeat")
+                .help("Perform W warmup runs before the measured repeats.")
+                .validator(|v| {
+                    v.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("--warmup takes a non-negative integer"))
+                })
+                .long_help(
+                    "\
+Runs `command` W additional times before --repeat kicks in, and
+discards the results of those runs. Useful for letting caches warm
+up before the runs that are actually measured.",
+                ),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .value_name("INTERVAL_MS")
+                .help("Sample the child's resident memory over its lifetime.")
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(0) | Err(_) => {
+                        Err(String::from("--sample takes a positive number of milliseconds"))
+                    }
+                    Ok(_) => Ok(()),
+                })
+                .long_help(
+                    "\
+Polls the child's resident set size every INTERVAL_MS (default: 10ms)
+from the moment it is spawned until it exits, and reports mean,
+p50/p90/p99, and time-to-peak RSS, along with a sparkline of the
+RSS curve. On platforms without /proc, falls back to a single
+sample taken from ru_maxrss once the child has exited.
+
+Because this flag takes an optional value, an interval must be given
+with `=` (e.g. `--sample=5`); a bare `--sample` uses the 10ms default,
+but `--sample 5` would instead try to parse `5` as the command to run.",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-real")
+                .long("max-real")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("Fail if real time exceeds DURATION (e.g. 500ms, 2s, 1m30s).")
+                .validator(|v| parse_duration(&v).map(|_| ()))
+                .long_help(
+                    "\
+Turns tally into a regression gate: if the measured real time
+exceeds DURATION, a warning is printed and tally exits 124, even
+if `command` itself succeeded. Combine with --max-user, --max-mem,
+and --max-faults to bound CPU time, peak memory, and page faults
+the same way. With --repeat, the worst run among the measured
+repeats is checked against each budget.",
+                ),
+        )
+        .arg(
+            Arg::with_name("max-user")
+                .long("max-user")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("Fail if user CPU time exceeds DURATION (e.g. 500ms, 2s, 1m30s).")
+                .validator(|v| parse_duration(&v).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("max-mem")
+                .long("max-mem")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Fail if peak memory exceeds SIZE (e.g. 128MB, 2GB).")
+                .validator(|v| parse_size(&v).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("max-faults")
+                .long("max-faults")
+                .takes_value(true)
+                .value_name("N")
+                .help("Fail if the total number of page faults exceeds N.")
+                .validator(|v| {
+                    v.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("--max-faults takes a non-negative integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Output a single JSON object instead of the usual formats.")
+                .conflicts_with_all(&["posix", "gnu", "delimited"])
+                .long_help(
+                    "\
+Writes a single JSON object containing all collected fields --
+user_ns, system_ns, real_ns, peak_mem_kb, major_faults, minor_faults,
+exit_code, and (when available) signal/core_dumped -- instead of the
+line-oriented delimited format. With --repeat, the object instead
+holds a \"runs\" array of per-run results plus a \"stats\" object of
+aggregate statistics, and with --sample each run additionally carries
+an \"rss_series\" array.",
                 ),
         )
         .usage("tally time [options] command [arguments]...")
@@ -108,100 +739,150 @@ value. The metrics are:
         );
     let matches = app.clone().get_matches();
 
-    let mut usage = rusage {
-        ru_utime: timeval {
-            tv_sec: 0 as time_t,
-            tv_usec: 0 as suseconds_t,
-        },
-        ru_stime: timeval {
-            tv_sec: 0 as time_t,
-            tv_usec: 0 as suseconds_t,
-        },
-        ru_maxrss: 0 as c_long,
-        ru_ixrss: 0 as c_long,
-        ru_idrss: 0 as c_long,
-        ru_isrss: 0 as c_long,
-        ru_minflt: 0 as c_long,
-        ru_majflt: 0 as c_long,
-        ru_nswap: 0 as c_long,
-        ru_inblock: 0 as c_long,
-        ru_oublock: 0 as c_long,
-        ru_msgsnd: 0 as c_long,
-        ru_msgrcv: 0 as c_long,
-        ru_nsignals: 0 as c_long,
-        ru_nvcsw: 0 as c_long,
-        ru_nivcsw: 0 as c_long,
-    };
-
     let (cmd, cmd_args) = matches.subcommand();
     if cmd.is_empty() {
         app.print_long_help().unwrap();
         process::exit(127);
     }
+    let cmd_args = cmd_args.unwrap();
 
-    let mut command = Command::new(cmd);
-    if let Some(args) = cmd_args.unwrap().values_of("") {
-        command.args(args);
-    }
+    let warmup: usize = matches
+        .value_of("warmup")
+        .map(|w| w.parse().unwrap())
+        .unwrap_or(0);
+    let repeat: usize = matches
+        .value_of("repeat")
+        .map(|r| r.parse().unwrap())
+        .unwrap_or(1);
+    let sample_interval = if matches.is_present("sample") {
+        let ms = matches.value_of("sample").unwrap_or("10").parse().unwrap();
+        Some(Duration::from_millis(ms))
+    } else {
+        None
+    };
 
-    let mut child = match command.spawn() {
-        Ok(child) => child,
-        Err(e) => {
-            use std::io::ErrorKind;
-            match e.kind() {
-                ErrorKind::NotFound => {
-                    process::exit(127);
-                }
-                ErrorKind::PermissionDenied => {
-                    process::exit(126);
-                }
-                _ => {}
-            }
-            match e.raw_os_error() {
-                Some(e) if e > 0 && e <= 125 => {
-                    process::exit(125);
-                }
-                _ => process::exit(1),
-            }
+    let mut runs = Vec::with_capacity(warmup + repeat);
+    for i in 0..(warmup + repeat) {
+        let stats = run_once(cmd, cmd_args, sample_interval);
+        if i >= warmup {
+            runs.push(stats);
         }
-    };
+    }
+    let exit = runs.last().unwrap().exit_code;
 
-    let start = time::PreciseTime::now();
-    let exit = child.wait();
-    let end = time::PreciseTime::now();
-    let exit = match exit {
-        Ok(exit) => {
-            match exit.code() {
-                Some(exit) => exit,
-                None => {
-                    // signal
-                    1
-                }
-            }
+    let aggregate_mode = matches.is_present("repeat");
+    if matches.is_present("json") {
+        if aggregate_mode {
+            render_json_aggregate(&runs);
+        } else {
+            render_json_single(&runs[0]);
         }
-        Err(_) => 1,
+    } else if aggregate_mode {
+        render_aggregate(&matches, &runs);
+    } else {
+        render_single(&matches, &runs[0]);
+    }
+
+    let budgets = Budgets {
+        max_real_ns: matches.value_of("max-real").map(|v| parse_duration(v).unwrap()),
+        max_user_ns: matches.value_of("max-user").map(|v| parse_duration(v).unwrap()),
+        max_mem_kb: matches.value_of("max-mem").map(|v| parse_size(v).unwrap() / 1024),
+        max_faults: matches.value_of("max-faults").map(|v| v.parse().unwrap()),
     };
+    // against a regression gate, a benchmark is only as good as its worst run
+    let worst_real = runs.iter().map(|r| r.real_ns).max().unwrap();
+    let worst_user = runs.iter().map(|r| r.user_ns).max().unwrap();
+    let worst_mem = runs.iter().map(|r| r.peak_mem).max().unwrap() as u64;
+    let worst_faults = runs
+        .iter()
+        .map(|r| (r.major_faults + r.minor_faults) as u64)
+        .max()
+        .unwrap();
+    let violations = check_budgets(&budgets, worst_real, worst_user, worst_mem, worst_faults);
+    if !violations.is_empty() {
+        use ansi_term::Colour;
+        for violation in &violations {
+            eprintln!("{}", Colour::Red.paint(format!("budget exceeded: {}", violation)));
+        }
+        process::exit(124);
+    }
 
-    match unsafe { getrusage(RUSAGE_CHILDREN, (&mut usage) as *mut rusage) } {
-        0 => {}
-        _ => process::exit(exit),
+    process::exit(exit);
+}
+
+/// Hand-serializes a single run into a flat JSON object.
+fn run_to_json(run: &RunStats) -> String {
+    let mut obj = format!(
+        "{{\"user_ns\":{},\"system_ns\":{},\"real_ns\":{},\"peak_mem_kb\":{},\
+         \"major_faults\":{},\"minor_faults\":{},\"exit_code\":{}",
+        run.user_ns,
+        run.system_ns,
+        run.real_ns,
+        run.peak_mem,
+        run.major_faults,
+        run.minor_faults,
+        run.exit_code,
+    );
+    if let Some(signal) = run.signal {
+        obj.push_str(&format!(
+            ",\"signal\":{},\"core_dumped\":{}",
+            signal, run.core_dumped
+        ));
     }
+    if let Some(ref series) = run.rss_series {
+        let points: Vec<String> = series
+            .iter()
+            .map(|&(ns, kb)| format!("[{},{}]", ns, kb))
+            .collect();
+        obj.push_str(&format!(",\"rss_series\":[{}]", points.join(",")));
+    }
+    obj.push('}');
+    obj
+}
 
-    let real_time = start.to(end);
-    let ns: u64 = if let Some(ns) = real_time.num_nanoseconds() {
-        ns as u64 - real_time.num_seconds() as u64 * 1_000_000_000
-    } else if let Some(us) = real_time.num_microseconds() {
-        us as u64 - real_time.num_seconds() as u64 * 1_000_000
-    } else {
-        let ms = real_time.num_milliseconds();
-        ms as u64 - real_time.num_seconds() as u64 * 1_000
-    };
+fn aggregate_to_json(a: &Aggregate) -> String {
+    format!(
+        "{{\"min\":{},\"max\":{},\"mean\":{},\"median\":{},\"stddev\":{},\"total\":{}}}",
+        a.min, a.max, a.mean, a.median, a.stddev, a.total
+    )
+}
+
+fn render_json_single(run: &RunStats) {
+    eprintln!("{}", run_to_json(run));
+}
+
+fn render_json_aggregate(runs: &[RunStats]) {
+    let runs_json: Vec<String> = runs.iter().map(run_to_json).collect();
+
+    let user: Vec<f64> = runs.iter().map(|r| r.user_ns as f64).collect();
+    let system: Vec<f64> = runs.iter().map(|r| r.system_ns as f64).collect();
+    let real: Vec<f64> = runs.iter().map(|r| r.real_ns as f64).collect();
+    let peak_mem: Vec<f64> = runs.iter().map(|r| r.peak_mem as f64).collect();
+    let major_faults: Vec<f64> = runs.iter().map(|r| r.major_faults as f64).collect();
+    let minor_faults: Vec<f64> = runs.iter().map(|r| r.minor_faults as f64).collect();
+
+    eprintln!(
+        "{{\"runs\":[{}],\"stats\":{{\"user\":{},\"system\":{},\"real\":{},\
+         \"peak_mem\":{},\"major_faults\":{},\"minor_faults\":{}}}}}",
+        runs_json.join(","),
+        aggregate_to_json(&aggregate(&user)),
+        aggregate_to_json(&aggregate(&system)),
+        aggregate_to_json(&aggregate(&real)),
+        aggregate_to_json(&aggregate(&peak_mem)),
+        aggregate_to_json(&aggregate(&major_faults)),
+        aggregate_to_json(&aggregate(&minor_faults)),
+    );
+}
+
+fn render_single(matches: &ArgMatches, run: &RunStats) {
+    let usage_maxrss = run.peak_mem;
+    let usage_majflt = run.major_faults;
+    let usage_minflt = run.minor_faults;
+    let utime_ns = run.user_ns;
+    let stime_ns = run.system_ns;
+    let rtime_ns = run.real_ns;
+    let ns = rtime_ns % 1_000_000_000;
 
-    let utime_ns =
-        usage.ru_utime.tv_sec as u64 * 1_000_000_000 + usage.ru_utime.tv_usec as u64 * 1_000;
-    let stime_ns =
-        usage.ru_stime.tv_sec as u64 * 1_000_000_000 + usage.ru_stime.tv_usec as u64 * 1_000;
-    let rtime_ns = real_time.num_seconds() as u64 * 1_000_000_000 + ns;
     let ns_to_ms_frac = |ns: u64| {
         format!(
             "{}.{:03}",
@@ -217,10 +898,9 @@ value. The metrics are:
             ns_to_ms_frac(utime_ns),
             ns_to_ms_frac(stime_ns),
         );
-        process::exit(exit);
     } else if matches.is_present("gnu") {
         let mut pretty_time = String::new();
-        let mut t = real_time.num_seconds();
+        let mut t = (rtime_ns / 1_000_000_000) as i64;
         if t / 3600 > 0 {
             pretty_time.push_str(&format!("{}:", t / 3600));
         }
@@ -228,7 +908,7 @@ value. The metrics are:
         pretty_time.push_str(&format!("{}:", t / 60));
         t = t % 60;
         pretty_time.push_str(&format!("{:02}", t));
-        pretty_time.push_str(&format!(".{:03}", (rtime_ns % 1_000_000_000) / 1_000_000));
+        pretty_time.push_str(&format!(".{:03}", ns / 1_000_000));
         eprintln!(
             "\
              {}user {}system {}elapsed {:.1}%CPU ({}text+{}data {}max)k\n\
@@ -239,14 +919,13 @@ value. The metrics are:
             (utime_ns + stime_ns) as f64 / rtime_ns as f64,
             0, // deprecated
             0, // deprecated
-            usage.ru_maxrss,
-            usage.ru_inblock,
-            usage.ru_oublock,
-            usage.ru_majflt,
-            usage.ru_minflt,
+            usage_maxrss,
+            run.in_blocks,
+            run.out_blocks,
+            usage_majflt,
+            usage_minflt,
             0, // deprecated
         );
-        process::exit(exit);
     } else if let Some(d) = matches.value_of("delimited") {
         use std::io;
 
@@ -268,110 +947,242 @@ value. The metrics are:
         wrt.write_field(b"real").unwrap();
         wrt.write_record(&[format!("{}", rtime_ns)]).unwrap();
         wrt.write_field(b"peak_mem").unwrap();
-        wrt.write_record(&[format!("{}", usage.ru_maxrss)]).unwrap();
+        wrt.write_record(&[format!("{}", usage_maxrss)]).unwrap();
         wrt.write_field(b"major_faults").unwrap();
-        wrt.write_record(&[format!("{}", usage.ru_majflt)]).unwrap();
+        wrt.write_record(&[format!("{}", usage_majflt)]).unwrap();
         wrt.write_field(b"minor_faults").unwrap();
-        wrt.write_record(&[format!("{}", usage.ru_minflt)]).unwrap();
-        drop(wrt);
-        process::exit(exit);
-    }
-
-    use ansi_term::Colour;
-    let unitc = |u| Colour::White.dimmed().paint(u);
-    let unit = |v, u| format!("{}{}", v, unitc(u));
-
-    // we want to show the same units on every row
-    let has_h =
-        real_time.num_hours() > 0 || usage.ru_utime.tv_sec > 3600 || usage.ru_stime.tv_sec > 3600;
-    let has_m = has_h || real_time.num_minutes() > 0 || usage.ru_utime.tv_sec > 60 ||
-        usage.ru_stime.tv_sec > 60;
-    let has_usec = usage.ru_utime.tv_usec % 1_000 > 0 || usage.ru_stime.tv_usec % 1_000 > 0;
-    let has_msec = has_usec || usage.ru_utime.tv_usec > 1_000 || usage.ru_stime.tv_usec > 1_000;
-
-    let pretty_seconds = |mut s| {
-        let mut pretty_time = String::new();
-        if has_h {
-            pretty_time.push_str(&unit(format!("{:>2}", s / 3600), "h "));
-        }
-        s = s % 3600;
-        if has_h || has_m {
-            pretty_time.push_str(&unit(format!("{:>2}", s / 60), "m "));
+        wrt.write_record(&[format!("{}", usage_minflt)]).unwrap();
+        if let Some(signal) = run.signal {
+            wrt.write_field(b"signal").unwrap();
+            wrt.write_record(&[format!("{}", signal)]).unwrap();
+            wrt.write_field(b"core_dumped").unwrap();
+            wrt.write_record(&[format!("{}", run.core_dumped)]).unwrap();
         }
-        s = s % 60;
-        pretty_time.push_str(&unit(format!("{:>2}", s), "s"));
-        pretty_time
-    };
-    let pretty_time = |t: &timeval| {
-        let mut s = pretty_seconds(t.tv_sec);
-        let mut usec = t.tv_usec;
-        if has_msec {
-            s.push_str(" ");
-            s.push_str(&unit(format!("{:>3}", usec / 1_000), "ms"));
+    } else {
+        use ansi_term::Colour;
+        let unitc = |u| Colour::White.dimmed().paint(u);
+        let unit = |v, u| format!("{}{}", v, unitc(u));
+
+        // we want to show the same units on every row
+        let has_h = rtime_ns / 1_000_000_000 / 3600 > 0;
+        let has_m = has_h || rtime_ns / 1_000_000_000 / 60 > 0;
+        let has_usec = (ns / 1_000) % 1_000 > 0;
+        let has_msec = has_usec || ns / 1_000_000 > 0;
+
+        let pretty_seconds = |mut s| {
+            let mut pretty_time = String::new();
+            if has_h {
+                pretty_time.push_str(&unit(format!("{:>2}", s / 3600), "h "));
+            }
+            s = s % 3600;
+            if has_h || has_m {
+                pretty_time.push_str(&unit(format!("{:>2}", s / 60), "m "));
+            }
+            s = s % 60;
+            pretty_time.push_str(&unit(format!("{:>2}", s), "s"));
+            pretty_time
+        };
+        let pretty_time = |total_ns: u64| {
+            let mut s = pretty_seconds((total_ns / 1_000_000_000) as i64);
+            let mut rem = total_ns % 1_000_000_000;
+            if has_msec {
+                s.push_str(" ");
+                s.push_str(&unit(format!("{:>3}", rem / 1_000_000), "ms"));
+            }
+            rem = rem % 1_000_000;
+            if has_usec {
+                s.push_str(" ");
+                s.push_str(&unit(format!("{:>3}", rem / 1_000), "µs"));
+            }
+            s
+        };
+        let pretty_mem = |ks| if ks > 10 * 1024 * 1024 {
+            unit(format!("{:.0} ", ks as f64 / 1024f64 / 1024f64), "GB")
+        } else if ks > 1024 * 1024 {
+            unit(format!("{:.1} ", ks as f64 / 1024f64 / 1024f64), "GB")
+        } else if ks > 10 * 1024 {
+            unit(format!("{:.0} ", ks as f64 / 1024f64), "MB")
+        } else if ks > 1024 {
+            unit(format!("{:.1} ", ks as f64 / 1024f64), "MB")
+        } else {
+            unit(format!("{} ", ks), "kB")
+        };
+
+        eprintln!(
+            "\
+             {}\n\
+             \n\
+             {} {}\n\
+             {} {}\n\
+             {} {}\n\n\
+             {} {}\n\
+             {} {}, {}\n\
+             \n{}",
+            Colour::White
+                .dimmed()
+                .paint(format!("{:-^45}", " [stats] ")),
+            Colour::Yellow.paint(format!("{:>15}", "user time:")),
+            pretty_time(utime_ns),
+            Colour::Yellow.paint(format!("{:>15}", "system time:")),
+            pretty_time(stime_ns),
+            Colour::Yellow.paint(format!("{:>15}", "real time:")),
+            pretty_time(rtime_ns),
+            Colour::Yellow.paint(format!("{:>15}", "max memory:")),
+            pretty_mem(usage_maxrss),
+            Colour::Yellow.paint(format!("{:>15}", "page faults:")),
+            unit(format!("{}", usage_majflt), "major"),
+            unit(format!("{}", usage_minflt), "minor"),
+            Colour::White.dimmed().paint(format!("{:-^45}", "")),
+        );
+
+        if let Some(signal) = run.signal {
+            eprintln!(
+                "\n{}",
+                Colour::Red.paint(format!(
+                    "terminated by {}{}",
+                    signal_name(signal),
+                    if run.core_dumped { " (core dumped)" } else { "" },
+                ))
+            );
         }
-        usec = usec % 1_000;
-        if has_usec {
-            s.push_str(" ");
-            s.push_str(&unit(format!("{:>3}", usec), "µs"));
+
+        if let Some(ref series) = run.rss_series {
+            let s = summarize_rss(series);
+            eprintln!(
+                "\n\
+                 {} {}\n\
+                 {} {} {} {}\n\
+                 {} {}\n\
+                 {} {}",
+                Colour::Yellow.paint(format!("{:>15}", "rss mean:")),
+                pretty_mem(s.mean as c_long),
+                Colour::Yellow.paint(format!("{:>15}", "rss p50/p90/p99:")),
+                pretty_mem(s.p50),
+                pretty_mem(s.p90),
+                pretty_mem(s.p99),
+                Colour::Yellow.paint(format!("{:>15}", "time to peak:")),
+                pretty_time(s.time_to_peak_ns),
+                Colour::Yellow.paint(format!("{:>15}", "rss curve:")),
+                s.sparkline,
+            );
         }
-        s
-    };
-    let pretty_time2 = || {
-        let mut s = pretty_seconds(real_time.num_seconds());
-        let mut ns = ns;
-        if has_msec {
-            s.push_str(" ");
-            s.push_str(&unit(format!("{:>3}", ns / 1_000_000), "ms"));
+    }
+}
+
+fn render_aggregate(matches: &ArgMatches, runs: &[RunStats]) {
+    let user: Vec<f64> = runs.iter().map(|r| r.user_ns as f64).collect();
+    let system: Vec<f64> = runs.iter().map(|r| r.system_ns as f64).collect();
+    let real: Vec<f64> = runs.iter().map(|r| r.real_ns as f64).collect();
+    let peak_mem: Vec<f64> = runs.iter().map(|r| r.peak_mem as f64).collect();
+    let major_faults: Vec<f64> = runs.iter().map(|r| r.major_faults as f64).collect();
+    let minor_faults: Vec<f64> = runs.iter().map(|r| r.minor_faults as f64).collect();
+
+    let metrics: [(&str, &[f64]); 6] = [
+        ("user", &user),
+        ("system", &system),
+        ("real", &real),
+        ("peak_mem", &peak_mem),
+        ("major_faults", &major_faults),
+        ("minor_faults", &minor_faults),
+    ];
+
+    if matches.is_present("posix") || matches.is_present("gnu") {
+        // POSIX only reports real/user/sys, same as the single-run path; GNU
+        // additionally reports memory and fault stats.
+        let selected: &[(&str, &[f64])] = if matches.is_present("gnu") {
+            &metrics
+        } else {
+            &metrics[..3]
+        };
+        for &(name, values) in selected.iter() {
+            let a = aggregate(values);
+            let fmt = metric_fmt(name);
+            eprintln!(
+                "{} min {} max {} mean {} median {} stddev {}",
+                name,
+                fmt(a.min),
+                fmt(a.max),
+                fmt(a.mean),
+                fmt(a.median),
+                fmt(a.stddev),
+            );
         }
-        if has_usec {
-            s.push_str(" ");
-            s.push_str(&unit(format!("{:>3}", ns / 1_000), "µs"));
+        return;
+    }
+
+    if let Some(d) = matches.value_of("delimited") {
+        use std::io;
+
+        let mut w = csv::WriterBuilder::new();
+        let delim = d.chars().next().unwrap();
+        let mut b = [0; 1];
+        delim.encode_utf8(&mut b);
+        w.delimiter(b[0]);
+        let stderr = io::stderr();
+        let handle = stderr.lock();
+        let mut wrt = w.from_writer(handle);
+        for &(name, values) in metrics.iter() {
+            let a = aggregate(values);
+            let stats: [(&str, f64); 6] = [
+                ("min", a.min),
+                ("max", a.max),
+                ("mean", a.mean),
+                ("median", a.median),
+                ("stddev", a.stddev),
+                ("total", a.total),
+            ];
+            for &(stat, value) in stats.iter() {
+                wrt.write_field(format!("{}_{}", name, stat).into_bytes())
+                    .unwrap();
+                wrt.write_record(&[format!("{}", value)]).unwrap();
+            }
         }
-        ns = ns % 1_000;
-        if ns != 0 {
-            s.push_str(" ");
-            s.push_str(&unit(format!("{:>3}", ns), "ns"));
+        if let Some(signal) = runs.last().and_then(|r| r.signal) {
+            wrt.write_field(b"signal").unwrap();
+            wrt.write_record(&[format!("{}", signal)]).unwrap();
+            wrt.write_field(b"core_dumped").unwrap();
+            wrt.write_record(&[format!("{}", runs.last().unwrap().core_dumped)])
+                .unwrap();
         }
-        s
-    };
-    let pretty_mem = |ks| if ks > 10 * 1024 * 1024 {
-        unit(format!("{:.0} ", ks as f64 / 1024f64 / 1024f64), "GB")
-    } else if ks > 1024 * 1024 {
-        unit(format!("{:.1} ", ks as f64 / 1024f64 / 1024f64), "GB")
-    } else if ks > 10 * 1024 {
-        unit(format!("{:.0} ", ks as f64 / 1024f64), "MB")
-    } else if ks > 1024 {
-        unit(format!("{:.1} ", ks as f64 / 1024f64), "MB")
-    } else {
-        unit(format!("{} ", ks), "kB")
-    };
+        return;
+    }
 
-    // now for our new and pretty output format
+    use ansi_term::Colour;
     eprintln!(
-        "\
-         {}\n\
-         \n\
-         {} {}\n\
-         {} {}\n\
-         {} {}\n\n\
-         {} {}\n\
-         {} {}, {}\n\
-         \n{}",
+        "{}",
         Colour::White
             .dimmed()
-            .paint(format!("{:-^45}", " [stats] ")),
-        Colour::Yellow.paint(format!("{:>15}", "user time:")),
-        pretty_time(&usage.ru_utime),
-        Colour::Yellow.paint(format!("{:>15}", "system time:")),
-        pretty_time(&usage.ru_stime),
-        Colour::Yellow.paint(format!("{:>15}", "real time:")),
-        pretty_time2(),
-        Colour::Yellow.paint(format!("{:>15}", "max memory:")),
-        pretty_mem(usage.ru_maxrss),
-        Colour::Yellow.paint(format!("{:>15}", "page faults:")),
-        unit(format!("{}", usage.ru_majflt), "major"),
-        unit(format!("{}", usage.ru_minflt), "minor"),
-        Colour::White.dimmed().paint(format!("{:-^45}", "")),
+            .paint(format!("{:-^60}", format!(" [stats: {} runs] ", runs.len())))
     );
-    process::exit(exit);
+    for &(name, values) in metrics.iter() {
+        let a = aggregate(values);
+        let fmt = metric_fmt(name);
+        eprintln!(
+            "{} min {}, max {}, mean {}, median {}, stddev {}",
+            Colour::Yellow.paint(format!("{:>15}", format!("{}:", name))),
+            fmt(a.min),
+            fmt(a.max),
+            fmt(a.mean),
+            fmt(a.median),
+            fmt(a.stddev),
+        );
+    }
+    eprintln!(
+        "{} {}",
+        Colour::Yellow.paint(format!("{:>15}", "total real time:")),
+        fmt_ns(aggregate(&real).total),
+    );
+    eprintln!("{}", Colour::White.dimmed().paint(format!("{:-^60}", "")));
+
+    if let Some(signal) = runs.last().and_then(|r| r.signal) {
+        let core_dumped = runs.last().unwrap().core_dumped;
+        eprintln!(
+            "\n{}",
+            Colour::Red.paint(format!(
+                "last run terminated by {}{}",
+                signal_name(signal),
+                if core_dumped { " (core dumped)" } else { "" },
+            ))
+        );
+    }
 }